@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils::errors::SomeError;
+
+/// On-disk cache of already-generated delta patches, keyed by the content hash of both sides
+/// of the diff plus the patch algorithm used. Lets a rerun over mostly-unchanged builds skip
+/// straight past the (slow) patcher for every file pair it has seen before.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PatchCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+}
+
+#[derive(Serialize, Deserialize, Hash, Eq, PartialEq)]
+struct CacheKey {
+    old_hash: String,
+    new_hash: String,
+    algorithm: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    patch_path: PathBuf,
+    patch_hash: String,
+}
+
+impl PatchCache {
+    fn cache_file(cache_dir: &Path) -> PathBuf {
+        cache_dir.join("patch_cache.bincode")
+    }
+
+    /// Loads the cache from `cache_dir`, starting empty if it doesn't exist yet or fails to
+    /// parse (e.g. a file in an older cache format). Entries written for a different patch
+    /// algorithm are kept but never match, since the algorithm is part of the key.
+    pub fn load(cache_dir: &Path) -> PatchCache {
+        fs::read(Self::cache_file(cache_dir))
+            .ok()
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, cache_dir: &Path) -> Result<(), SomeError> {
+        fs::create_dir_all(cache_dir)
+            .map_err(|e| SomeError(format!("Could not create cache dir {}: {}", cache_dir.display(), e)))?;
+        let bytes = bincode::serialize(self)
+            .map_err(|e| SomeError(format!("Could not serialize patch cache: {}", e)))?;
+        fs::write(Self::cache_file(cache_dir), bytes)
+            .map_err(|e| SomeError(format!("Could not write patch cache {}: {}", cache_dir.display(), e)))
+    }
+
+    /// Returns the cached patch path for this (old, new, algorithm) triple, if present and the
+    /// patch file the cache points at still exists on disk.
+    pub fn get(&self, old_hash: &str, new_hash: &str, algorithm: &str) -> Option<&PathBuf> {
+        let entry = self.entries.get(&CacheKey {
+            old_hash: old_hash.to_string(),
+            new_hash: new_hash.to_string(),
+            algorithm: algorithm.to_string(),
+        })?;
+        entry.patch_path.exists().then_some(&entry.patch_path)
+    }
+
+    pub fn insert(
+        &mut self,
+        old_hash: String,
+        new_hash: String,
+        algorithm: String,
+        patch_path: PathBuf,
+        patch_hash: String,
+    ) {
+        self.entries.insert(
+            CacheKey { old_hash, new_hash, algorithm },
+            CacheEntry { patch_path, patch_hash },
+        );
+    }
+}
+
+/// Hashes a file's contents with SHA-256, used as the cache key component for each side of a
+/// patch pair.
+pub fn hash_file(path: &Path) -> Result<String, SomeError> {
+    let bytes = fs::read(path).map_err(|e| SomeError(format!("Could not read {}: {}", path.display(), e)))?;
+    let mut hasher = Sha256::new();
+    hasher.update(&bytes);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_run_with_identical_hashes_reuses_the_cached_patch() {
+        let dir = std::env::temp_dir().join(format!("bouf_patch_cache_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let patch_path = dir.join("file.patch");
+        fs::write(&patch_path, b"fake patch contents").unwrap();
+        let patch_hash = hash_file(&patch_path).unwrap();
+
+        let mut cache = PatchCache::default();
+        assert!(cache.get("old-hash", "new-hash", "bsdiff").is_none());
+
+        cache.insert(
+            "old-hash".to_string(),
+            "new-hash".to_string(),
+            "bsdiff".to_string(),
+            patch_path.clone(),
+            patch_hash,
+        );
+        cache.save(&dir).unwrap();
+
+        // A second run loads the saved cache fresh and, given the same hashes, finds the patch
+        // already there instead of invoking the patcher again.
+        let reloaded = PatchCache::load(&dir);
+        assert_eq!(reloaded.get("old-hash", "new-hash", "bsdiff"), Some(&patch_path));
+        // A different new-file hash means the content actually changed, so it's a cache miss.
+        assert!(reloaded.get("old-hash", "other-hash", "bsdiff").is_none());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
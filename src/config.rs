@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
-use clap::Parser;
-use serde::Deserialize;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
 use toml;
 
 use crate::utils::errors::SomeError;
@@ -56,14 +57,87 @@ pub struct MainArgs {
     /// Clear existing output directory
     #[clap(short, long, value_parser, default_value_t = false)]
     pub clear_output: bool,
+    /// Disable the delta-patch cache; always regenerate every patch
+    #[clap(long, value_parser, default_value_t = false)]
+    pub no_cache: bool,
+
+    /// Increase log verbosity (-v, -vv, -vvv). Overridden by `BOUF_LOG` if set.
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Select a `[profiles.<name>]` table to deep-merge onto the base config
+    #[clap(long, value_parser, value_name = "stable|beta|nightly|...")]
+    pub profile: Option<String>,
+
+    /// Pipeline stage to run. Omit to run the full pipeline end-to-end (legacy behaviour).
+    #[clap(subcommand)]
+    pub command: Option<Stage>,
+}
+
+/// A single resumable pipeline stage.
+///
+/// Each stage reads `PipelineState` left behind by whichever stage ran before it (if any).
+/// `prepare` and `generate` write their state back to `EnvOptions::output_dir` so the next
+/// stage (possibly in a later invocation) can pick up where it left off; `package` and `post`
+/// only read it. Running with no subcommand chains prepare, generate, package and post in
+/// order; `validate` is not part of that chain.
+#[derive(Subcommand, Debug)]
+pub enum Stage {
+    /// Copy the build tree (PDB stripping and codesigning are not run)
+    Prepare,
+    /// Write a patch per file shared with the previous builds (a copy of the new file; no
+    /// patcher runs) and an empty `{}` manifest
+    Generate,
+    /// Check that the prepared build tree exists (no installer, zip or updater package is built)
+    Package,
+    /// Move the build to the "old builds" folder if `post.move_to_old` is set (the manifest is
+    /// not signed)
+    Post,
+    /// Validate the config (tool binaries, signing settings and input paths) without running
+    /// any stage
+    Validate,
+}
+
+/// Intermediate state one pipeline stage hands off to the next, persisted alongside the
+/// build output so a later `bouf <stage>` invocation can resume without redoing earlier work.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PipelineState {
+    /// Build tree produced by the `prepare` stage
+    pub prepared_dir: PathBuf,
+    /// Manifest produced by the `generate` stage
+    pub manifest_path: PathBuf,
+    /// Delta patches produced by the `generate` stage
+    pub patches_dir: PathBuf,
+}
+
+impl PipelineState {
+    fn state_path(output_dir: &Path) -> PathBuf {
+        output_dir.join("bouf_state.json")
+    }
+
+    pub fn load(output_dir: &Path) -> Result<PipelineState, SomeError> {
+        let path = Self::state_path(output_dir);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| SomeError(format!("Could not read pipeline state {}: {}", path.display(), e)))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| SomeError(format!("Could not parse pipeline state {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, output_dir: &Path) -> Result<(), SomeError> {
+        let path = Self::state_path(output_dir);
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| SomeError(format!("Could not serialize pipeline state: {}", e)))?;
+        fs::write(&path, contents)
+            .map_err(|e| SomeError(format!("Could not write pipeline state {}: {}", path.display(), e)))
+    }
 }
 
 fn get_default_branch() -> String {
     String::from("stable")
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub env: EnvOptions,
     pub prepare: PreparationOptions,
@@ -71,10 +145,14 @@ pub struct Config {
     pub package: PackageOptions,
     pub post: PostOptions,
     pub obs_version: ObsVersion,
+    /// Named overlays selectable with `--profile`, each deep-merged onto the rest of this
+    /// config. Kept as raw TOML rather than a typed `Config` since a profile may only set a
+    /// handful of fields.
+    pub profiles: HashMap<String, toml::Value>,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct ObsVersion {
     pub version_str: String,
     pub version_major: u8,
@@ -84,8 +162,8 @@ pub struct ObsVersion {
     pub rc: u8,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct EnvOptions {
     #[serde(default = "get_default_branch")]
     pub branch: String,
@@ -97,25 +175,28 @@ pub struct EnvOptions {
     pub makensis_path: PathBuf,
     pub pandoc_path: PathBuf,
     pub pdbcopy_path: PathBuf,
+    // Delta patch cache
+    pub cache_dir: PathBuf,
+    pub no_cache: bool,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct PreparationOptions {
     pub copy: CopyOptions,
     pub codesign: CodesignOptions,
     pub strip_pdbs: StripPDBOptions,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct CopyOptions {
     pub excludes: Vec<String>,
     pub overrides: Vec<(String, String)>,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct CodesignOptions {
     pub skip_sign: bool,
     pub sign_name: String,
@@ -124,53 +205,53 @@ pub struct CodesignOptions {
     pub sign_exts: Vec<String>,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct StripPDBOptions {
     pub exclude: Vec<String>,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct GenerationOptions {
     // patch_type: String,
     pub removed_files: Vec<String>,
     pub packages: Vec<ManifestPackageOptions>,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct ManifestPackageOptions {
     pub name: String,
     pub include_files: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct PackageOptions {
     pub installer: InstallerOptions,
     pub zip: ZipOptions,
     pub updater: UpdaterOptions,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct InstallerOptions {
     pub nsis_script: PathBuf,
     pub name: String,
     pub skip_sign: bool,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct ZipOptions {
     pub name: String,
     pub pdb_name: String,
     pub skip_for_prerelease: bool,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct UpdaterOptions {
     pub skip_sign: bool,
     pub notes_files: PathBuf,
@@ -180,8 +261,8 @@ pub struct UpdaterOptions {
     pub skip_for_prerelease: bool,
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct PostOptions {
     pub move_to_old: bool,
 }
@@ -237,56 +318,285 @@ impl Config {
         self.prepare.codesign.skip_sign = args.skip_codesigning;
         self.package.installer.skip_sign = args.skip_codesigning;
         self.package.updater.skip_sign = !args.skip_manifest_signing;
+        // `--no-cache` is a bare flag: it can only be passed (true) or omitted, never passed as
+        // an explicit `false`. So only ever turn caching off here, rather than unconditionally
+        // assigning and clobbering whatever the config file/profile/env override already set.
+        if args.no_cache {
+            self.env.no_cache = true;
+        }
+        if self.env.cache_dir.as_os_str().is_empty() {
+            self.env.cache_dir = self.env.output_dir.join("cache");
+        }
 
         // Todo remaining args
     }
 
+    /// Validates the config, collecting every problem found instead of stopping at the first
+    /// one so a single run can report all of them at once.
     pub fn validate(&mut self, check_binaries: bool, check_paths: bool) -> Result<(), SomeError> {
+        let mut errors: Vec<String> = Vec::new();
+
         // Check file paths (for binaries, also check if they are in %PATH%)
         if check_binaries {
-            misc::check_binary_path(&mut self.env.pdbcopy_path)?;
-            misc::check_binary_path(&mut self.env.makensis_path)?;
-            misc::check_binary_path(&mut self.env.sevenzip_path)?;
-            misc::check_binary_path(&mut self.env.pandoc_path)?;
+            for path in [
+                &mut self.env.pdbcopy_path,
+                &mut self.env.makensis_path,
+                &mut self.env.sevenzip_path,
+                &mut self.env.pandoc_path,
+            ] {
+                if let Err(e) = misc::check_binary_path(path) {
+                    errors.push(e.to_string());
+                }
+            }
         }
         // Check if private key is set correctly (if signing is enabled)
-        if !self.package.updater.skip_sign {
-            if env::var("UPDATER_PRIVATE_KEY").is_err() {
-                if let Err(e) = fs::metadata(&self.package.updater.private_key) {
-                    return Err(SomeError(format!("Private key not found: {}", e)));
-                }
+        if !self.package.updater.skip_sign && env::var("UPDATER_PRIVATE_KEY").is_err() {
+            if let Err(e) = fs::metadata(&self.package.updater.private_key) {
+                errors.push(format!("Private key not found: {}", e));
             }
         }
         // Check if codesigning parameters are set (if enabled)
         if !self.prepare.codesign.skip_sign {
-            // ToDo
+            let codesign = &self.prepare.codesign;
+            if codesign.sign_name.is_empty() {
+                errors.push("Codesigning is enabled but `sign_name` is empty".to_string());
+            }
+            if codesign.sign_digest.is_empty() {
+                errors.push("Codesigning is enabled but `sign_digest` is empty".to_string());
+            }
+            if codesign.sign_ts_serv.is_empty() {
+                errors.push("Codesigning is enabled but `sign_ts_serv` is empty".to_string());
+            }
+            if codesign.sign_exts.is_empty() {
+                errors.push("Codesigning is enabled but `sign_exts` is empty".to_string());
+            }
+            for ext in &codesign.sign_exts {
+                if !ext.starts_with('.') {
+                    errors.push(format!(
+                        "Codesign extension '{}' looks wrong, expected a leading '.' (e.g. \".exe\")",
+                        ext
+                    ));
+                }
+            }
         }
         // Check file/directory paths
         if check_paths {
             // Output folder cannot be checked as it may not exist yet
             match fs::canonicalize(&self.env.input_dir) {
                 Ok(res) => self.env.input_dir = res,
-                Err(e) => return Err(SomeError(format!("Input dir error: {}", e))),
+                Err(e) => errors.push(format!("Input dir error: {}", e)),
             }
             match fs::canonicalize(&self.env.previous_dir) {
                 Ok(res) => self.env.previous_dir = res,
-                Err(e) => return Err(SomeError(format!("Previous dir error: {}", e))),
+                Err(e) => errors.push(format!("Previous dir error: {}", e)),
             }
             // Check other files (nsis script, updater, vcredist)
+            match fs::canonicalize(&self.package.installer.nsis_script) {
+                Ok(res) => self.package.installer.nsis_script = res,
+                Err(e) => errors.push(format!("NSIS script error: {}", e)),
+            }
+            match fs::canonicalize(&self.package.updater.updater_path) {
+                Ok(res) => self.package.updater.updater_path = res,
+                Err(e) => errors.push(format!("Updater binary error: {}", e)),
+            }
+            match fs::canonicalize(&self.package.updater.vc_redist_path) {
+                Ok(res) => self.package.updater.vc_redist_path = res,
+                Err(e) => errors.push(format!("VC redist error: {}", e)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SomeError(format!(
+                "Config validation failed:\n  - {}",
+                errors.join("\n  - ")
+            )))
         }
+    }
+
+    /// Parses a config file, rejecting unknown keys and reporting the offending field and
+    /// TOML location instead of a generic failure.
+    pub fn from_file(path: &Path) -> Result<Config, SomeError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| SomeError(format!("Could not read config {}: {}", path.display(), e)))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| SomeError(format!("Could not parse config {}: {}", path.display(), e)))
+    }
+
+    /// Deep-merges the `[profiles.<name>]` table onto the rest of the config. Fields the
+    /// profile sets override the base; fields it omits keep whatever the base config had.
+    /// Call after `from_file` and before `apply_env_overrides`/`apply_args` — see the
+    /// precedence order documented on [`Config::apply_env_overrides`].
+    pub fn apply_profile(&mut self, name: &str) -> Result<(), SomeError> {
+        let profile = self
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| SomeError(format!("No such profile: {}", name)))?;
+
+        let mut merged = toml::Value::try_from(&*self)
+            .map_err(|e| SomeError(format!("Could not serialize config for profile merge: {}", e)))?;
+        merge_toml_table(&mut merged, profile);
 
+        *self = merged
+            .try_into()
+            .map_err(|e| SomeError(format!("Could not apply profile '{}': {}", name, e)))?;
         Ok(())
     }
 
-    pub fn from_file(path: &Path) -> Config {
-        let config: Option<Config> = fs::read_to_string(path)
-            .ok()
-            .and_then(|fc| toml::from_str(fc.as_str()).ok());
+    /// Applies `BOUF_<SECTION>__<FIELD>`-style environment overrides on top of the config,
+    /// e.g. `BOUF_ENV__INPUT_DIR=/path` overrides `env.input_dir`, and
+    /// `BOUF_PACKAGE__INSTALLER__NAME=foo.exe` overrides `package.installer.name`. Section and
+    /// field names are matched case-insensitively.
+    ///
+    /// A `BOUF_`-prefixed var with no `__` separator (e.g. `BOUF_LOG`, which configures the
+    /// `tracing` filter, not a config field) is not a config path and is left alone — every
+    /// real `Config` field lives at least two levels deep (section, then field), so a single
+    /// segment never names one.
+    ///
+    /// Overall precedence, lowest to highest: struct defaults < base config file <
+    /// `[profiles.<name>]` < environment variables < CLI args (applied last, in
+    /// [`Config::apply_args`]).
+    pub fn apply_env_overrides(&mut self) -> Result<(), SomeError> {
+        let mut merged = toml::Value::try_from(&*self)
+            .map_err(|e| SomeError(format!("Could not serialize config for env overrides: {}", e)))?;
 
-        if config.is_none() {
-            panic!("Failed to parse config!")
+        for (key, value) in env::vars() {
+            if let Some(path) = key.strip_prefix("BOUF_") {
+                let segments: Vec<String> = path.split("__").map(|s| s.to_lowercase()).collect();
+                if segments.len() < 2 {
+                    continue;
+                }
+                set_toml_path(&mut merged, &segments, value);
+            }
+        }
+
+        *self = merged
+            .try_into()
+            .map_err(|e| SomeError(format!("Could not apply environment overrides: {}", e)))?;
+        Ok(())
+    }
+}
+
+/// Deep-merges `overlay` onto `base` in place: tables are merged key by key (recursively); any
+/// other value in `overlay` replaces `base`'s outright.
+fn merge_toml_table(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml_table(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
         }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Sets `base.segments[0].segments[1]...` to `value`, creating intermediate tables as needed.
+fn set_toml_path(base: &mut toml::Value, segments: &[String], value: String) {
+    let Some((head, rest)) = segments.split_first() else {
+        return;
+    };
+    if !base.is_table() {
+        *base = toml::Value::Table(Default::default());
+    }
+    let table = base.as_table_mut().expect("just ensured this is a table");
+
+    if rest.is_empty() {
+        table.insert(head.clone(), parse_env_value(&value));
+    } else {
+        let entry = table
+            .entry(head.clone())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+        set_toml_path(entry, rest, value);
+    }
+}
+
+/// Best-effort typing of an environment variable's string value so it can be merged into a
+/// TOML tree that may expect a bool/int/float rather than a string.
+fn parse_env_value(value: &str) -> toml::Value {
+    if let Ok(b) = value.parse::<bool>() {
+        toml::Value::Boolean(b)
+    } else if let Ok(i) = value.parse::<i64>() {
+        toml::Value::Integer(i)
+    } else if let Ok(f) = value.parse::<f64>() {
+        toml::Value::Float(f)
+    } else {
+        toml::Value::String(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_collects_every_codesign_error_instead_of_stopping_at_the_first() {
+        let mut config = Config::default();
+        // Isolate the codesigning branch: skip the private key check, which would otherwise
+        // also fail here and make this test depend on the ambient environment.
+        config.package.updater.skip_sign = true;
+        config.prepare.codesign.skip_sign = false;
+        config.prepare.codesign.sign_exts = vec!["exe".to_string()];
+
+        let err = config.validate(false, false).unwrap_err().to_string();
+
+        assert!(err.contains("sign_name"), "{err}");
+        assert!(err.contains("sign_digest"), "{err}");
+        assert!(err.contains("sign_ts_serv"), "{err}");
+        assert!(err.contains("leading '.'"), "{err}");
+    }
+
+    #[test]
+    fn merge_toml_table_overlay_wins_but_missing_keys_survive() {
+        let mut base: toml::Value = toml::from_str("a = 1\n[nested]\nx = 1\ny = 2\n").unwrap();
+        let overlay: toml::Value = toml::from_str("a = 2\n[nested]\nx = 9\n").unwrap();
+
+        merge_toml_table(&mut base, overlay);
+
+        assert_eq!(base["a"].as_integer(), Some(2));
+        assert_eq!(base["nested"]["x"].as_integer(), Some(9));
+        // Present in the base but absent from the overlay: left untouched.
+        assert_eq!(base["nested"]["y"].as_integer(), Some(2));
+    }
+
+    #[test]
+    fn set_toml_path_creates_intermediate_tables() {
+        let mut base = toml::Value::Table(Default::default());
+
+        set_toml_path(
+            &mut base,
+            &["package".to_string(), "installer".to_string(), "name".to_string()],
+            "foo.exe".to_string(),
+        );
+
+        assert_eq!(base["package"]["installer"]["name"].as_str(), Some("foo.exe"));
+    }
+
+    #[test]
+    fn parse_env_value_coerces_bools_and_numbers_but_falls_back_to_string() {
+        assert_eq!(parse_env_value("true"), toml::Value::Boolean(true));
+        assert_eq!(parse_env_value("42"), toml::Value::Integer(42));
+        assert_eq!(parse_env_value("3.5"), toml::Value::Float(3.5));
+        assert_eq!(parse_env_value("stable"), toml::Value::String("stable".to_string()));
+    }
+
+    #[test]
+    fn env_overrides_ignore_vars_with_no_section_separator() {
+        // BOUF_LOG (no '__') configures the tracing filter, not a config field, and must not
+        // become a bogus top-level key that trips `deny_unknown_fields`.
+        env::set_var("BOUF_TESTONLY_NO_SEPARATOR", "true");
+        let mut config = Config::default();
+
+        let result = config.apply_env_overrides();
 
-        config.unwrap()
+        env::remove_var("BOUF_TESTONLY_NO_SEPARATOR");
+        assert!(result.is_ok());
     }
 }
@@ -0,0 +1,22 @@
+use tracing_subscriber::EnvFilter;
+
+/// Sets up the global `tracing` subscriber.
+///
+/// `BOUF_LOG` (same syntax as `RUST_LOG`) takes precedence when set; otherwise the filter
+/// level is derived from the CLI's `-v` count (0 = warn, 1 = info, 2 = debug, 3+ = trace).
+/// Must be called before `Config::from_file` so config parsing is covered by the logs.
+pub fn init(verbose: u8) {
+    let default_level = match verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+
+    let filter = EnvFilter::try_from_env("BOUF_LOG").unwrap_or_else(|_| EnvFilter::new(default_level));
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .init();
+}
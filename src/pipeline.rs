@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::Path;
+
+use tracing::{debug, info, instrument, warn};
+
+use crate::cache::{hash_file, PatchCache};
+use crate::config::{Config, MainArgs, PipelineState, Stage};
+use crate::logging;
+use crate::utils::errors::SomeError;
+
+/// Patch algorithm tag stored in the patch cache key alongside each pair's content hashes.
+const PATCH_ALGORITHM: &str = "bsdiff";
+
+/// Top-level entry point a `main()` hands a parsed `MainArgs` to. Sets up logging first (so
+/// config parsing itself is covered by it, per `logging::init`'s doc), loads and resolves the
+/// config, then runs whichever stage `args.command` selects, resuming from whatever
+/// `PipelineState` an earlier invocation left in `config.env.output_dir`. With no subcommand,
+/// chains prepare, generate, package and post in order; the validate stage is not part of
+/// that chain.
+pub fn run(args: &MainArgs) -> Result<(), SomeError> {
+    logging::init(args.verbose);
+
+    let mut config = Config::from_file(&args.config)?;
+    if let Some(profile) = &args.profile {
+        config.apply_profile(profile)?;
+    }
+    config.apply_env_overrides()?;
+    config.apply_args(args);
+
+    match &args.command {
+        Some(Stage::Prepare) => run_prepare(&mut config),
+        Some(Stage::Generate) => run_generate(&mut config),
+        Some(Stage::Package) => run_package(&mut config),
+        Some(Stage::Post) => run_post(&mut config),
+        Some(Stage::Validate) => config.validate(true, true),
+        None => {
+            run_prepare(&mut config)?;
+            run_generate(&mut config)?;
+            run_package(&mut config)?;
+            run_post(&mut config)
+        }
+    }
+}
+
+#[instrument(skip(config))]
+fn run_prepare(config: &mut Config) -> Result<(), SomeError> {
+    let mut state = PipelineState::load(&config.env.output_dir).unwrap_or_default();
+
+    state.prepared_dir = config.env.output_dir.join("prepared");
+    info!(from = %config.env.input_dir.display(), to = %state.prepared_dir.display(), "copying build tree");
+    copy_dir_recursive(&config.env.input_dir, &state.prepared_dir)?;
+    // This stage only copies the build tree: codesigning and PDB stripping
+    // (prepare.codesign / prepare.strip_pdbs) are not run over state.prepared_dir.
+
+    state.save(&config.env.output_dir)
+}
+
+#[instrument(skip(config))]
+fn run_generate(config: &mut Config) -> Result<(), SomeError> {
+    let mut state = PipelineState::load(&config.env.output_dir)?;
+
+    state.patches_dir = config.env.output_dir.join("patches");
+    fs::create_dir_all(&state.patches_dir)
+        .map_err(|e| SomeError(format!("Could not create {}: {}", state.patches_dir.display(), e)))?;
+
+    if config.env.no_cache {
+        warn!("patch cache disabled (--no-cache), regenerating every patch");
+    }
+    let mut cache = if config.env.no_cache {
+        PatchCache::default()
+    } else {
+        PatchCache::load(&config.env.cache_dir)
+    };
+
+    for entry in fs::read_dir(&config.env.previous_dir)
+        .map_err(|e| SomeError(format!("Could not read {}: {}", config.env.previous_dir.display(), e)))?
+    {
+        let entry = entry.map_err(|e| {
+            SomeError(format!("Could not read entry in {}: {}", config.env.previous_dir.display(), e))
+        })?;
+        let old_path = entry.path();
+        if !old_path.is_file() {
+            continue;
+        }
+
+        let file_name = entry.file_name();
+        let new_path = state.prepared_dir.join(&file_name);
+        if !new_path.is_file() {
+            debug!(file = %file_name.to_string_lossy(), "removed in new build, skipping patch");
+            continue;
+        }
+
+        let old_hash = hash_file(&old_path)?;
+        let new_hash = hash_file(&new_path)?;
+        let patch_path = state.patches_dir.join(format!("{}.patch", file_name.to_string_lossy()));
+
+        if let Some(cached_path) = cache.get(&old_hash, &new_hash, PATCH_ALGORITHM) {
+            debug!(file = %file_name.to_string_lossy(), "reusing cached patch, skipping the patcher");
+            // A patch cached at this very path is already in place; copying a file onto itself
+            // would truncate it.
+            if cached_path != &patch_path {
+                fs::copy(cached_path, &patch_path).map_err(|e| {
+                    SomeError(format!("Could not reuse cached patch {}: {}", cached_path.display(), e))
+                })?;
+            }
+            continue;
+        }
+
+        debug!(file = %file_name.to_string_lossy(), "generating delta patch");
+        // No patcher is invoked: the file written as the patch is a copy of new_path.
+        fs::copy(&new_path, &patch_path)
+            .map_err(|e| SomeError(format!("Could not generate patch {}: {}", patch_path.display(), e)))?;
+
+        let patch_hash = hash_file(&patch_path)?;
+        cache.insert(old_hash, new_hash, PATCH_ALGORITHM.to_string(), patch_path, patch_hash);
+    }
+
+    if !config.env.no_cache {
+        cache.save(&config.env.cache_dir)?;
+    }
+    debug!(dir = %state.patches_dir.display(), "patch generation complete");
+
+    state.manifest_path = config.env.output_dir.join("manifest.json");
+    fs::write(&state.manifest_path, "{}")
+        .map_err(|e| SomeError(format!("Could not write {}: {}", state.manifest_path.display(), e)))?;
+    info!(manifest = %state.manifest_path.display(), "generate stage complete");
+
+    state.save(&config.env.output_dir)
+}
+
+#[instrument(skip(config))]
+fn run_package(config: &mut Config) -> Result<(), SomeError> {
+    let state = PipelineState::load(&config.env.output_dir)?;
+    if !state.prepared_dir.is_dir() {
+        return Err(SomeError(format!(
+            "Prepared build tree {} is missing; run the 'prepare' stage first",
+            state.prepared_dir.display()
+        )));
+    }
+    // No installer, zip or updater package (package.installer / package.zip / package.updater)
+    // is built here: the stage only checks that state.prepared_dir exists and logs its inputs.
+    if config.package.installer.skip_sign {
+        warn!("codesigning is disabled, packages will ship unsigned");
+    }
+    info!(
+        prepared_dir = %state.prepared_dir.display(),
+        manifest = %state.manifest_path.display(),
+        installer = %config.package.installer.name,
+        "package stage complete"
+    );
+    Ok(())
+}
+
+#[instrument(skip(config))]
+fn run_post(config: &mut Config) -> Result<(), SomeError> {
+    let state = PipelineState::load(&config.env.output_dir)?;
+    if config.post.move_to_old {
+        let dest = config.env.previous_dir.join(&config.obs_version.version_str);
+        if state.prepared_dir.is_dir() {
+            info!(to = %dest.display(), "moving build to old builds folder");
+            fs::rename(&state.prepared_dir, &dest)
+                .map_err(|e| SomeError(format!("Could not move build to {}: {}", dest.display(), e)))?;
+        } else {
+            warn!(dir = %state.prepared_dir.display(), "nothing to move, prepared dir is missing");
+        }
+    }
+    Ok(())
+}
+
+/// Recursively copies `src` onto `dst`, creating directories as needed. Used by the `prepare`
+/// stage to materialize the build tree it hands off to `generate`.
+fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<(), SomeError> {
+    fs::create_dir_all(dst).map_err(|e| SomeError(format!("Could not create {}: {}", dst.display(), e)))?;
+
+    for entry in
+        fs::read_dir(src).map_err(|e| SomeError(format!("Could not read {}: {}", src.display(), e)))?
+    {
+        let entry = entry.map_err(|e| SomeError(format!("Could not read entry in {}: {}", src.display(), e)))?;
+        let dest_path = dst.join(entry.file_name());
+        let file_type = entry
+            .file_type()
+            .map_err(|e| SomeError(format!("Could not stat {}: {}", entry.path().display(), e)))?;
+
+        if file_type.is_dir() {
+            copy_dir_recursive(&entry.path(), &dest_path)?;
+        } else {
+            fs::copy(entry.path(), &dest_path).map_err(|e| {
+                SomeError(format!("Could not copy {} to {}: {}", entry.path().display(), dest_path.display(), e))
+            })?;
+            debug!(file = %dest_path.display(), "copied file");
+        }
+    }
+
+    Ok(())
+}